@@ -1,37 +1,26 @@
-use std::{collections::HashSet, error::Error, sync::Arc};
+mod render_graph;
+mod renderer;
+mod shader_reload;
+
+use std::{error::Error, sync::Arc, time::Instant};
+
+use renderer::SurfaceBinding;
+use shader_reload::ShaderWatcher;
 
 use vulkano::{
     app_info_from_cargo_toml,
-    command_buffer::{AutoCommandBuffer, AutoCommandBufferBuilder, DynamicState},
-    descriptor::PipelineLayoutAbstract,
-    device::{Device, DeviceCreationError, DeviceExtensions, Features, Queue},
-    format::Format,
-    framebuffer::{
-        Framebuffer, FramebufferAbstract, FramebufferCreationError, RenderPassAbstract,
-        RenderPassCreationError, Subpass,
-    },
-    image::{swapchain::SwapchainImage, ImageUsage},
     instance::{
         debug::{DebugCallback, DebugCallbackCreationError, MessageSeverity, MessageType},
         layers_list, Instance, InstanceCreationError, InstanceExtensions, PhysicalDevice,
     },
-    pipeline::{
-        vertex::{BufferlessDefinition, BufferlessVertices},
-        viewport::Viewport,
-        GraphicsPipeline,
-    },
-    single_pass_renderpass,
-    swapchain::{
-        acquire_next_image, AcquireError, CapabilitiesError, ColorSpace, CompositeAlpha,
-        FullscreenExclusive, PresentMode, Surface, Swapchain, SwapchainCreationError,
-    },
-    sync::{self, FlushError, GpuFuture, SharingMode},
+    swapchain::{acquire_next_image, AcquireError},
+    sync::{self, FlushError, GpuFuture},
 };
 use vulkano_win::{required_extensions, VkSurfaceBuild};
 use winit::{
     event::{Event, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
-    window::{Window, WindowBuilder},
+    window::WindowBuilder,
 };
 
 fn print_layers_list() {
@@ -127,250 +116,11 @@ fn register_debug_callback(
     }))
 }
 
-fn create_device_and_queues(
-    instance: &Arc<Instance>,
-    surface: &Arc<Surface<Window>>,
-) -> Result<(Arc<Device>, Arc<Queue>, Arc<Queue>), DeviceCreationError> {
-    let (device, queues) = PhysicalDevice::enumerate(instance)
-        .filter_map(|device| {
-            let graphics_queue_family = device
-                .queue_families()
-                .find(|queue_family| queue_family.supports_graphics());
-            let present_queue_family = device
-                .queue_families()
-                .find(|queue_family| surface.is_supported(*queue_family) == Ok(true));
-            graphics_queue_family
-                .and(present_queue_family)
-                .and_then(|_| {
-                    // safe to unwrap
-                    let graphics_queue_family = graphics_queue_family.unwrap();
-                    let present_queue_family = present_queue_family.unwrap();
-
-                    let mut queue_families_set = HashSet::new();
-                    let unique_queue_families: Vec<_> =
-                        vec![graphics_queue_family, present_queue_family]
-                            .iter()
-                            .filter(|queue_family| queue_families_set.insert(queue_family.id()))
-                            .map(|queue_family| queue_family.to_owned())
-                            .collect();
-                    Some((device, unique_queue_families))
-                })
-        })
-        .map(|(device, queue_families)| {
-            let extensions = DeviceExtensions {
-                khr_swapchain: true,
-                ..DeviceExtensions::supported_by_device(device)
-            };
-            Device::new(
-                device,
-                &Features::none(),
-                &extensions,
-                queue_families
-                    .iter()
-                    .map(|queue_family| (*queue_family, 1.0)),
-            )
-        })
-        .filter(|device| device.is_ok())
-        .next()
-        .ok_or(DeviceCreationError::FeatureNotPresent)??; // If nothing found, return DeviceCreationError::FeatureNotPresent
-    let queues: Vec<Arc<Queue>> = queues.collect();
-    let graphics_queue = queues
-        .iter()
-        .find(|queue| queue.family().supports_graphics())
-        .unwrap(); // Must safe
-    let present_queue = queues
-        .iter()
-        .find(|queue| surface.is_supported(queue.family()) == Ok(true))
-        .unwrap(); // Must safe
-    Ok((device, graphics_queue.clone(), present_queue.clone()))
-}
-
-fn create_swapchain(
-    surface: &Arc<Surface<Window>>,
-    device: &Arc<Device>,
-    graphics_queue: &Arc<Queue>,
-    present_queue: &Arc<Queue>,
-) -> Result<(Arc<Swapchain<Window>>, Vec<Arc<SwapchainImage<Window>>>), SwapchainCreationError> {
-    let capabilities = surface
-        .capabilities(device.physical_device())
-        .map_err(|e| match e {
-            CapabilitiesError::OomError(e) => SwapchainCreationError::OomError(e),
-            CapabilitiesError::SurfaceLost => SwapchainCreationError::SurfaceLost,
-        })?;
-    let num_images = capabilities
-        .max_image_count
-        .unwrap_or(capabilities.min_image_count + 1)
-        .min(capabilities.min_image_count + 1);
-    let dimensions = if let Some(dimensions) = capabilities.current_extent {
-        dimensions
-    } else {
-        let [w, h]: [u32; 2] = surface.window().inner_size().into();
-        let [min_w, min_h] = capabilities.min_image_extent;
-        let [max_w, max_h] = capabilities.max_image_extent;
-        // clamp width and height
-        [min_w.max(max_w.min(w)), min_h.max(max_h.min(h))]
-    };
-    let layers = 1; // Usually 1
-    let image_usage = ImageUsage {
-        color_attachment: true,
-        ..ImageUsage::none()
-    };
-    let sharing = if graphics_queue.family() == present_queue.family() {
-        SharingMode::from(graphics_queue)
-    } else {
-        SharingMode::from(vec![graphics_queue, present_queue].as_slice())
-    };
-    let clipped = true;
-    Swapchain::new(
-        device.clone(),
-        surface.clone(),
-        num_images,
-        Format::B8G8R8A8Unorm,
-        dimensions,
-        layers,
-        image_usage,
-        sharing,
-        capabilities.current_transform,
-        CompositeAlpha::Opaque,
-        PresentMode::Fifo,
-        FullscreenExclusive::Default,
-        clipped,
-        ColorSpace::SrgbNonLinear,
-    )
-}
-
-fn create_render_pass(
-    device: &Arc<Device>,
-    color_format: Format,
-) -> Result<Arc<dyn RenderPassAbstract + Send + Sync>, RenderPassCreationError> {
-    Ok(Arc::new(single_pass_renderpass!(device.clone(),
-        attachments: {
-            color: {
-                load: Clear,
-                store: Store,
-                format: color_format,
-                samples: 1,
-            }
-        },
-        pass: {
-            color: [color],
-            depth_stencil: {}
-        }
-    )?))
-}
-
-fn create_graphics_pipeline(
-    device: &Arc<Device>,
-    dimensions: [f32; 2],
-    render_pass: &Arc<dyn RenderPassAbstract + Send + Sync>,
-) -> Result<
-    Arc<
-        GraphicsPipeline<
-            BufferlessDefinition,
-            Box<dyn PipelineLayoutAbstract + Send + Sync>,
-            Arc<dyn RenderPassAbstract + Send + Sync>,
-        >,
-    >,
-    Box<dyn Error>,
-> {
-    mod vertex_shader {
-        vulkano_shaders::shader! {
-            ty: "vertex",
-            path: "src/shader/triangle.vert"
-        }
-    }
-    mod fragment_shader {
-        vulkano_shaders::shader! {
-            ty: "fragment",
-            path: "src/shader/triangle.frag"
-        }
-    }
-    let vertex_shader = vertex_shader::Shader::load(device.clone())?;
-    let fragment_shader = fragment_shader::Shader::load(device.clone())?;
-    let viewport = Viewport {
-        origin: [0.0, 0.0],
-        dimensions,
-        depth_range: 0.0..1.0,
-    };
-    Ok(Arc::new(
-        GraphicsPipeline::start()
-            .vertex_input(BufferlessDefinition)
-            .vertex_shader(vertex_shader.main_entry_point(), ())
-            .fragment_shader(fragment_shader.main_entry_point(), ())
-            .triangle_list()
-            .viewports(vec![viewport])
-            .depth_clamp(false)
-            .polygon_mode_fill()
-            .line_width(1.0)
-            .cull_mode_back()
-            .front_face_clockwise()
-            .blend_pass_through()
-            .render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
-            .build(device.clone())?,
-    ))
-}
-
-fn create_framebuffers(
-    swapchain_images: &[Arc<SwapchainImage<Window>>],
-    render_pass: &Arc<dyn RenderPassAbstract + Send + Sync>,
-) -> Result<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>, FramebufferCreationError> {
-    swapchain_images
-        .iter()
-        .map(|image| {
-            Framebuffer::start(render_pass.clone())
-                .add(image.clone())
-                .unwrap()
-                .build()
-                .map(|framebuffer| {
-                    let framebuffer: Arc<dyn FramebufferAbstract + Send + Sync> =
-                        Arc::new(framebuffer);
-                    framebuffer
-                })
-        })
-        .collect()
-}
-
-fn create_command_buffers(
-    framebuffers: &[Arc<dyn FramebufferAbstract + Send + Sync>],
-    device: &Arc<Device>,
-    graphics_queue: &Arc<Queue>,
-    graphics_pipeline: &Arc<
-        GraphicsPipeline<
-            BufferlessDefinition,
-            Box<dyn PipelineLayoutAbstract + Send + Sync>,
-            Arc<dyn RenderPassAbstract + Send + Sync>,
-        >,
-    >,
-) -> Result<Vec<Arc<AutoCommandBuffer>>, Box<dyn Error>> {
-    framebuffers
-        .iter()
-        .map(|framebuffer| {
-            let vertices = BufferlessVertices {
-                vertices: 3, // triangle
-                instances: 1,
-            };
-            let command_buffer = AutoCommandBufferBuilder::primary_simultaneous_use(
-                device.clone(),
-                graphics_queue.family(),
-            )?
-            .begin_render_pass(
-                framebuffer.clone(),
-                false,
-                vec![[0.0, 0.0, 0.0, 1.0].into()],
-            )?
-            .draw(
-                graphics_pipeline.clone(),
-                &DynamicState::none(),
-                vertices,
-                (),
-                (),
-            )?
-            .end_render_pass()?
-            .build()?;
-            Ok(Arc::new(command_buffer))
-        })
-        .collect()
-}
+/// Number of frames the CPU is allowed to record ahead of the GPU. Each
+/// frame owns a slot in `frame_futures`, so the event loop does not stall on
+/// the single previous frame: it blocks only on the slot that last used the
+/// swapchain image it just acquired (see `images_in_flight`).
+const MAX_FRAMES_IN_FLIGHT: usize = 2;
 
 fn main() -> Result<(), Box<dyn Error>> {
     print_layers_list();
@@ -388,26 +138,21 @@ fn main() -> Result<(), Box<dyn Error>> {
         .build_vk_surface(&event_loop, instance.clone())
         .unwrap();
 
-    let (device, graphics_queue, present_queue) = create_device_and_queues(&instance, &surface)?;
-    let (mut swapchain, mut swapchain_images) =
-        create_swapchain(&surface, &device, &graphics_queue, &present_queue)?;
-
-    let mut render_pass = create_render_pass(&device, swapchain.format())?;
-    let mut graphics_pipeline = create_graphics_pipeline(
-        &device,
-        [
-            swapchain.dimensions()[0] as _,
-            swapchain.dimensions()[1] as _,
-        ],
-        &render_pass,
-    )?;
-
-    let mut framebuffers: Vec<_> = create_framebuffers(&swapchain_images, &render_pass)?;
-    let mut command_buffers: Vec<_> =
-        create_command_buffers(&framebuffers, &device, &graphics_queue, &graphics_pipeline)?;
+    let mut binding = SurfaceBinding::new(&instance, surface, &renderer::TRIANGLE_VERTICES)?;
+    let shader_watcher = ShaderWatcher::new()?;
 
-    let mut prev_future: Option<Box<dyn GpuFuture>> = None;
+    let mut frame_futures: Vec<Option<Box<dyn GpuFuture>>> =
+        (0..MAX_FRAMES_IN_FLIGHT).map(|_| None).collect();
+    // Which `frame_futures` slot last wrote each swapchain image, so a frame
+    // that's about to reuse an image can wait for that earlier frame's GPU
+    // work to finish first instead of racing it (the image count is usually
+    // higher than `MAX_FRAMES_IN_FLIGHT`, so images get reused by a
+    // different frame slot well before that slot's own future is waited on).
+    let mut images_in_flight: Vec<Option<usize>> =
+        (0..binding.image_count()).map(|_| None).collect();
+    let mut current_frame = 0;
     let mut request_recreate_swapchain = false;
+    let start_time = Instant::now();
 
     event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Wait;
@@ -425,43 +170,69 @@ fn main() -> Result<(), Box<dyn Error>> {
                 request_recreate_swapchain = true;
             }
             Event::MainEventsCleared => {
-                surface.window().request_redraw();
+                if !shader_watcher.poll_changes().is_empty() {
+                    if let Err(e) = binding.reload_shaders() {
+                        eprintln!("shader reload: {}", e);
+                    }
+                }
+
+                binding.request_redraw();
             }
             Event::RedrawRequested(_) => {
-                if let Some(ref mut prev_future) = prev_future {
-                    prev_future.cleanup_finished();
+                current_frame = (current_frame + 1) % MAX_FRAMES_IN_FLIGHT;
+
+                if let Some(ref mut frame_future) = frame_futures[current_frame] {
+                    frame_future.cleanup_finished();
                 }
 
-                match acquire_next_image(swapchain.clone(), None) {
+                match acquire_next_image(binding.swapchain().clone(), None) {
                     Ok((image_index, suboptimal, acquire_future)) => {
-                        let command_buffer = command_buffers[image_index].clone();
+                        if let Some(owner_frame) = images_in_flight[image_index] {
+                            if owner_frame != current_frame {
+                                if let Some(owner_future) = &frame_futures[owner_frame] {
+                                    if let Err(e) = owner_future.wait(None) {
+                                        eprintln!("{}", e);
+                                    }
+                                }
+                            }
+                        }
+                        images_in_flight[image_index] = Some(current_frame);
+
+                        if let Err(e) = binding
+                            .update_uniform_buffer(image_index, start_time.elapsed().as_secs_f32())
+                        {
+                            eprintln!("{}", e);
+                        }
+
+                        let command_buffer = binding.command_buffer(image_index);
                         let future: Box<dyn GpuFuture> =
-                            if let Some(prev_future) = prev_future.take() {
-                                Box::new(prev_future.join(acquire_future))
+                            if let Some(frame_future) = frame_futures[current_frame].take() {
+                                Box::new(frame_future.join(acquire_future))
                             } else {
                                 Box::new(acquire_future)
                             };
                         if let Ok(future) =
-                            future.then_execute(graphics_queue.clone(), command_buffer)
+                            future.then_execute(binding.graphics_queue().clone(), command_buffer)
                         {
                             let future = future
                                 .then_swapchain_present(
-                                    present_queue.clone(),
-                                    swapchain.clone(),
+                                    binding.present_queue().clone(),
+                                    binding.swapchain().clone(),
                                     image_index,
                                 )
                                 .then_signal_fence_and_flush();
                             match future {
                                 Ok(future) => {
-                                    prev_future = Some(Box::new(future));
+                                    frame_futures[current_frame] = Some(Box::new(future));
                                 }
                                 Err(FlushError::OutOfDate) => {
                                     request_recreate_swapchain = true;
-                                    prev_future = Some(Box::new(sync::now(device.clone())));
+                                    frame_futures[current_frame] =
+                                        Some(Box::new(sync::now(binding.device().clone())));
                                 }
                                 Err(e) => {
                                     eprintln!("{}", e);
-                                    prev_future = None;
+                                    frame_futures[current_frame] = None;
                                 }
                             }
                         }
@@ -471,58 +242,26 @@ fn main() -> Result<(), Box<dyn Error>> {
                     }
                     Err(AcquireError::OutOfDate) => {
                         request_recreate_swapchain = true;
-                        prev_future = Some(Box::new(sync::now(device.clone())));
+                        frame_futures[current_frame] =
+                            Some(Box::new(sync::now(binding.device().clone())));
                     }
                     Err(e) => {
                         eprintln!("{}", e);
-                        prev_future = None;
+                        frame_futures[current_frame] = None;
                     }
                 }
 
                 if request_recreate_swapchain {
                     request_recreate_swapchain = false;
-                    let result = swapchain
-                        .recreate()
-                        .map_err(|e| e.to_string())
-                        .and_then(|(new_swapchain, new_swapchain_images)| {
-                            swapchain = new_swapchain;
-                            swapchain_images = new_swapchain_images;
-                            create_render_pass(&device, swapchain.format())
-                                .map_err(|e| e.to_string())
-                        })
-                        .and_then(|new_render_pass| {
-                            render_pass = new_render_pass;
-                            create_graphics_pipeline(
-                                &device,
-                                [
-                                    swapchain.dimensions()[0] as _,
-                                    swapchain.dimensions()[1] as _,
-                                ],
-                                &render_pass,
-                            )
-                            .map_err(|e| e.to_string())
-                        })
-                        .and_then(|new_graphics_pipeline| {
-                            graphics_pipeline = new_graphics_pipeline;
-                            create_framebuffers(&swapchain_images, &render_pass)
-                                .map_err(|e| e.to_string())
-                        })
-                        .and_then(|new_framebuffers| {
-                            framebuffers = new_framebuffers;
-                            create_command_buffers(
-                                &framebuffers,
-                                &device,
-                                &graphics_queue,
-                                &graphics_pipeline,
-                            )
-                            .map_err(|e| e.to_string())
-                        })
-                        .and_then(|new_command_buffers| {
-                            command_buffers = new_command_buffers;
-                            Ok(())
-                        });
-                    if let Err(msg) = result {
-                        eprintln!("{}", msg);
+                    match binding.recreate() {
+                        Ok(()) => {
+                            // The image count (and which images are which)
+                            // may have changed, so last frame's ownership
+                            // table no longer means anything.
+                            images_in_flight =
+                                (0..binding.image_count()).map(|_| None).collect();
+                        }
+                        Err(e) => eprintln!("{}", e),
                     }
                 }
             }
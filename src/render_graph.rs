@@ -0,0 +1,352 @@
+//! A small render graph: a [`PassDesc`] names the attachments a pass reads
+//! and writes, [`resolve`] orders the passes and builds each one's render
+//! pass/framebuffers, and [`record_command_buffers`] walks the result to
+//! record every pass into one command buffer per swapchain image. A pass
+//! that reads another pass's [`ColorOutput::Intermediate`] output can fetch
+//! it through [`ResolvedGraph::color_image`] to sample from.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::Arc,
+};
+
+use vulkano::{
+    command_buffer::{AutoCommandBuffer, AutoCommandBufferBuilder},
+    device::{Device, Queue},
+    format::{ClearValue, Format},
+    framebuffer::{Framebuffer, FramebufferAbstract, RenderPassAbstract, Subpass},
+    image::{attachment::AttachmentImage, swapchain::SwapchainImage, ImageUsage},
+    single_pass_renderpass,
+};
+use winit::window::Window;
+
+/// Where a pass's color attachment lives.
+pub enum ColorOutput {
+    /// The swapchain image being presented.
+    Swapchain,
+    /// An off-screen attachment private to the graph, reused across
+    /// swapchain images. If no later pass reads it, it goes back to the pool
+    /// right after its pass and may be aliased by a later intermediate
+    /// attachment of the same format; if a later pass reads it, it is never
+    /// returned to the pool.
+    Intermediate(Format),
+}
+
+/// Declares one pass: what it reads (by the name of the pass that produced
+/// it) and what it writes. The draw calls live in the closure passed to
+/// [`record_command_buffers`].
+pub struct PassDesc {
+    pub name: &'static str,
+    pub reads: Vec<&'static str>,
+    pub color_output: Option<ColorOutput>,
+    pub depth_output: bool,
+}
+
+struct ResolvedPass {
+    render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    framebuffers: Vec<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    clear_values: Vec<ClearValue>,
+    color_image: Option<Arc<AttachmentImage>>,
+}
+
+/// The output of [`resolve`]: one render pass and one framebuffer per
+/// swapchain image, per registered pass.
+pub struct ResolvedGraph {
+    order: Vec<&'static str>,
+    passes: HashMap<&'static str, ResolvedPass>,
+}
+
+impl ResolvedGraph {
+    pub fn order(&self) -> &[&'static str] {
+        &self.order
+    }
+
+    pub fn render_pass(&self, name: &str) -> &Arc<dyn RenderPassAbstract + Send + Sync> {
+        &self.passes[name].render_pass
+    }
+
+    fn framebuffer(&self, name: &str, image_index: usize) -> Arc<dyn FramebufferAbstract + Send + Sync> {
+        self.passes[name].framebuffers[image_index].clone()
+    }
+
+    fn clear_values(&self, name: &str) -> Vec<ClearValue> {
+        self.passes[name].clear_values.clone()
+    }
+
+    /// The `ColorOutput::Intermediate` image `name` wrote, or `None`.
+    pub fn color_image(&self, name: &str) -> Option<Arc<AttachmentImage>> {
+        self.passes[name].color_image.clone()
+    }
+}
+
+/// Topologically orders `passes` by `reads`.
+fn topological_order(passes: &[PassDesc]) -> Vec<&'static str> {
+    let mut remaining: Vec<&PassDesc> = passes.iter().collect();
+    let mut ordered = Vec::with_capacity(passes.len());
+    while !remaining.is_empty() {
+        let ready_index = remaining
+            .iter()
+            .position(|pass| pass.reads.iter().all(|dep| ordered.contains(dep)))
+            .expect("render graph has a read-dependency cycle");
+        ordered.push(remaining.remove(ready_index).name);
+    }
+    ordered
+}
+
+/// The part of `ImageUsage` the attachment-reuse pool in [`resolve`] keys on.
+fn usage_key(usage: ImageUsage) -> (bool, bool, bool) {
+    (
+        usage.color_attachment,
+        usage.depth_stencil_attachment,
+        usage.sampled,
+    )
+}
+
+/// Resolves `passes` against a swapchain: builds each pass's render pass
+/// and attachment images, and one framebuffer per swapchain image.
+pub fn resolve(
+    device: &Arc<Device>,
+    passes: &[PassDesc],
+    swapchain_images: &[Arc<SwapchainImage<Window>>],
+    swapchain_format: Format,
+) -> Result<ResolvedGraph, Box<dyn Error>> {
+    let order = topological_order(passes);
+    let image_count = swapchain_images.len();
+
+    // Attachment images free for reuse by a later pass of the same format/usage.
+    let mut free_images: HashMap<(Format, (bool, bool, bool)), Vec<Arc<AttachmentImage>>> =
+        HashMap::new();
+    let mut acquire_image = |format: Format, usage: ImageUsage| -> Result<Arc<AttachmentImage>, Box<dyn Error>> {
+        let key = (format, usage_key(usage));
+        if let Some(image) = free_images.get_mut(&key).and_then(Vec::pop) {
+            return Ok(image);
+        }
+        Ok(AttachmentImage::with_usage(
+            device.clone(),
+            swapchain_images[0].dimensions(),
+            format,
+            usage,
+        )?)
+    };
+
+    let mut resolved = HashMap::with_capacity(passes.len());
+    for (position, &name) in order.iter().enumerate() {
+        let pass = passes.iter().find(|pass| pass.name == name).unwrap();
+
+        let color_format = match pass.color_output {
+            Some(ColorOutput::Swapchain) => swapchain_format,
+            Some(ColorOutput::Intermediate(format)) => format,
+            None => swapchain_format,
+        };
+        let intermediate_color_usage = ImageUsage {
+            color_attachment: true,
+            sampled: true,
+            ..ImageUsage::none()
+        };
+        let intermediate_color = match pass.color_output {
+            Some(ColorOutput::Intermediate(format)) => {
+                Some(acquire_image(format, intermediate_color_usage)?)
+            }
+            _ => None,
+        };
+        let depth_usage = ImageUsage {
+            depth_stencil_attachment: true,
+            ..ImageUsage::none()
+        };
+        let depth_image = if pass.depth_output {
+            Some(acquire_image(Format::D16Unorm, depth_usage)?)
+        } else {
+            None
+        };
+
+        let render_pass = build_render_pass(device, color_format, pass.depth_output)?;
+
+        let framebuffers = (0..image_count)
+            .map(|image_index| {
+                let builder = Framebuffer::start(render_pass.clone());
+                let builder = if let Some(image) = &intermediate_color {
+                    builder.add(image.clone())?
+                } else {
+                    builder.add(swapchain_images[image_index].clone())?
+                };
+                let framebuffer: Arc<dyn FramebufferAbstract + Send + Sync> =
+                    if let Some(depth_image) = &depth_image {
+                        Arc::new(builder.add(depth_image.clone())?.build()?)
+                    } else {
+                        Arc::new(builder.build()?)
+                    };
+                Ok(framebuffer)
+            })
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+
+        let mut clear_values = vec![[0.0, 0.0, 0.0, 1.0].into()];
+        if pass.depth_output {
+            clear_values.push(1.0f32.into());
+        }
+
+        resolved.insert(
+            name,
+            ResolvedPass {
+                render_pass,
+                framebuffers,
+                clear_values,
+                color_image: intermediate_color.clone(),
+            },
+        );
+
+        // Still read by a later pass, so its images can't be reused yet.
+        let still_needed = order[position + 1..]
+            .iter()
+            .any(|&later| passes.iter().find(|pass| pass.name == later).unwrap().reads.contains(&name));
+        if !still_needed {
+            if let Some(image) = intermediate_color {
+                let key = (image.format(), usage_key(intermediate_color_usage));
+                free_images.entry(key).or_default().push(image);
+            }
+            if let Some(image) = depth_image {
+                let key = (image.format(), usage_key(depth_usage));
+                free_images.entry(key).or_default().push(image);
+            }
+        }
+    }
+
+    Ok(ResolvedGraph {
+        order,
+        passes: resolved,
+    })
+}
+
+fn build_render_pass(
+    device: &Arc<Device>,
+    color_format: Format,
+    with_depth: bool,
+) -> Result<Arc<dyn RenderPassAbstract + Send + Sync>, Box<dyn Error>> {
+    if with_depth {
+        Ok(Arc::new(single_pass_renderpass!(device.clone(),
+            attachments: {
+                color: {
+                    load: Clear,
+                    store: Store,
+                    format: color_format,
+                    samples: 1,
+                },
+                depth: {
+                    load: Clear,
+                    store: DontCare,
+                    format: Format::D16Unorm,
+                    samples: 1,
+                }
+            },
+            pass: {
+                color: [color],
+                depth_stencil: {depth}
+            }
+        )?))
+    } else {
+        Ok(Arc::new(single_pass_renderpass!(device.clone(),
+            attachments: {
+                color: {
+                    load: Clear,
+                    store: Store,
+                    format: color_format,
+                    samples: 1,
+                }
+            },
+            pass: {
+                color: [color],
+                depth_stencil: {}
+            }
+        )?))
+    }
+}
+
+/// The `Subpass` a pass's graphics pipeline renders into.
+pub fn subpass(graph: &ResolvedGraph, name: &str) -> Subpass<Arc<dyn RenderPassAbstract + Send + Sync>> {
+    Subpass::from(graph.render_pass(name).clone(), 0).unwrap()
+}
+
+/// Records every pass in `graph`'s topological order into one command
+/// buffer per swapchain image. `draw_pass` is called once per
+/// `(pass name, swapchain image index)` to issue that pass's draw calls.
+pub fn record_command_buffers<F>(
+    graph: &ResolvedGraph,
+    device: &Arc<Device>,
+    queue: &Arc<Queue>,
+    image_count: usize,
+    mut draw_pass: F,
+) -> Result<Vec<Arc<AutoCommandBuffer>>, Box<dyn Error>>
+where
+    F: FnMut(
+        AutoCommandBufferBuilder,
+        &ResolvedGraph,
+        &'static str,
+        usize,
+    ) -> Result<AutoCommandBufferBuilder, Box<dyn Error>>,
+{
+    let mut command_buffers = Vec::with_capacity(image_count);
+    for image_index in 0..image_count {
+        let mut builder =
+            AutoCommandBufferBuilder::primary_simultaneous_use(device.clone(), queue.family())?;
+        for &pass_name in graph.order() {
+            builder = builder.begin_render_pass(
+                graph.framebuffer(pass_name, image_index),
+                false,
+                graph.clear_values(pass_name),
+            )?;
+            builder = draw_pass(builder, graph, pass_name, image_index)?;
+            builder = builder.end_render_pass()?;
+        }
+        command_buffers.push(Arc::new(builder.build()?));
+    }
+    Ok(command_buffers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pass(name: &'static str, reads: Vec<&'static str>) -> PassDesc {
+        PassDesc {
+            name,
+            reads,
+            color_output: None,
+            depth_output: false,
+        }
+    }
+
+    #[test]
+    fn topological_order_runs_a_pass_after_what_it_reads() {
+        let passes = vec![
+            pass("post_process", vec!["shadow", "triangle"]),
+            pass("triangle", vec![]),
+            pass("shadow", vec![]),
+        ];
+        let order = topological_order(&passes);
+        assert_eq!(order.last(), Some(&"post_process"));
+        assert!(order.iter().position(|&n| n == "shadow").unwrap() < 2);
+        assert!(order.iter().position(|&n| n == "triangle").unwrap() < 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "cycle")]
+    fn topological_order_panics_on_a_cycle() {
+        let passes = vec![pass("a", vec!["b"]), pass("b", vec!["a"])];
+        topological_order(&passes);
+    }
+
+    #[test]
+    fn usage_key_distinguishes_attachment_usages_of_the_same_format() {
+        let color_sampled = ImageUsage {
+            color_attachment: true,
+            sampled: true,
+            ..ImageUsage::none()
+        };
+        let depth = ImageUsage {
+            depth_stencil_attachment: true,
+            ..ImageUsage::none()
+        };
+        assert_ne!(usage_key(color_sampled), usage_key(depth));
+        assert_eq!(usage_key(color_sampled), usage_key(color_sampled));
+    }
+}
@@ -0,0 +1,566 @@
+//! Window-surface-tied renderer state: [`SurfaceBinding`] owns the device,
+//! queues, swapchain, render graph, pipeline and command buffers, so a
+//! resize or shader hot-reload is a single method call.
+
+use std::{error::Error, path::Path, sync::Arc};
+
+use cgmath::{Matrix4, Point3, Rad, Vector3};
+
+use crate::render_graph::{self, ColorOutput, PassDesc, ResolvedGraph};
+use crate::shader_reload::CompiledShader;
+
+use vulkano::{
+    buffer::{BufferUsage, CpuAccessibleBuffer},
+    command_buffer::{AutoCommandBuffer, DynamicState},
+    descriptor::{
+        descriptor_set::{DescriptorSet, PersistentDescriptorSet},
+        PipelineLayoutAbstract,
+    },
+    device::{Device, DeviceCreationError, DeviceExtensions, Features, Queue},
+    format::Format,
+    framebuffer::RenderPassAbstract,
+    image::{swapchain::SwapchainImage, ImageUsage},
+    impl_vertex,
+    instance::{Instance, PhysicalDevice},
+    pipeline::{vertex::SingleBufferDefinition, viewport::Viewport, GraphicsPipeline},
+    swapchain::{
+        CapabilitiesError, ColorSpace, CompositeAlpha, FullscreenExclusive, PresentMode, Surface,
+        Swapchain, SwapchainCreationError,
+    },
+    sync::SharingMode,
+};
+use winit::window::Window;
+
+/// Matches the `position`/`color` vertex attributes `triangle.vert` declares.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct Vertex {
+    pub position: [f32; 2],
+    pub color: [f32; 3],
+}
+impl_vertex!(Vertex, position, color);
+
+/// The default geometry for [`SurfaceBinding::new`].
+pub const TRIANGLE_VERTICES: [Vertex; 3] = [
+    Vertex {
+        position: [0.0, -0.5],
+        color: [1.0, 0.0, 0.0],
+    },
+    Vertex {
+        position: [0.5, 0.5],
+        color: [0.0, 1.0, 0.0],
+    },
+    Vertex {
+        position: [-0.5, 0.5],
+        color: [0.0, 0.0, 1.0],
+    },
+];
+
+/// Matches `UniformBufferObject` in `triangle.vert`.
+#[derive(Debug, Clone, Copy)]
+struct UniformBufferObject {
+    model: Matrix4<f32>,
+    view: Matrix4<f32>,
+    proj: Matrix4<f32>,
+}
+
+const TRIANGLE_PASS: &str = "triangle";
+
+fn triangle_render_graph() -> Vec<PassDesc> {
+    vec![PassDesc {
+        name: TRIANGLE_PASS,
+        reads: Vec::new(),
+        color_output: Some(ColorOutput::Swapchain),
+        depth_output: true,
+    }]
+}
+
+const VERTEX_SHADER_PATH: &str = "src/shader/triangle.vert";
+const FRAGMENT_SHADER_PATH: &str = "src/shader/triangle.frag";
+
+fn compile_triangle_shaders(
+    device: &Arc<Device>,
+) -> Result<(CompiledShader, CompiledShader), String> {
+    let vertex_shader = CompiledShader::compile(device, Path::new(VERTEX_SHADER_PATH))?;
+    let fragment_shader = CompiledShader::compile(device, Path::new(FRAGMENT_SHADER_PATH))?;
+    Ok((vertex_shader, fragment_shader))
+}
+
+type Pipeline = GraphicsPipeline<
+    SingleBufferDefinition<Vertex>,
+    Box<dyn PipelineLayoutAbstract + Send + Sync>,
+    Arc<dyn RenderPassAbstract + Send + Sync>,
+>;
+
+fn create_graphics_pipeline(
+    device: &Arc<Device>,
+    dimensions: [f32; 2],
+    graph: &ResolvedGraph,
+    pass_name: &str,
+    vertex_shader: &CompiledShader,
+    fragment_shader: &CompiledShader,
+) -> Result<Arc<Pipeline>, Box<dyn Error>> {
+    let viewport = Viewport {
+        origin: [0.0, 0.0],
+        dimensions,
+        depth_range: 0.0..1.0,
+    };
+    Ok(Arc::new(
+        GraphicsPipeline::start()
+            .vertex_input_single_buffer::<Vertex>()
+            .vertex_shader(vertex_shader.vertex_main_entry_point(), ())
+            .fragment_shader(fragment_shader.fragment_main_entry_point(), ())
+            .triangle_list()
+            .viewports(vec![viewport])
+            .depth_clamp(false)
+            .polygon_mode_fill()
+            .line_width(1.0)
+            .cull_mode_back()
+            .front_face_clockwise()
+            .blend_pass_through()
+            .depth_stencil_simple_depth()
+            .render_pass(render_graph::subpass(graph, pass_name))
+            .build(device.clone())?,
+    ))
+}
+
+pub fn create_vertex_buffer(
+    device: &Arc<Device>,
+    vertices: &[Vertex],
+) -> Result<Arc<CpuAccessibleBuffer<[Vertex]>>, Box<dyn Error>> {
+    Ok(CpuAccessibleBuffer::from_iter(
+        device.clone(),
+        BufferUsage::vertex_buffer(),
+        false,
+        vertices.iter().copied(),
+    )?)
+}
+
+/// One MVP uniform buffer per swapchain image. Overwriting it safely while a
+/// prior frame's GPU work may still be reading it relies on `main`'s
+/// `images_in_flight` table, not on anything here.
+fn create_uniform_buffers(
+    device: &Arc<Device>,
+    count: usize,
+) -> Result<Vec<Arc<CpuAccessibleBuffer<UniformBufferObject>>>, Box<dyn Error>> {
+    (0..count)
+        .map(|_| {
+            Ok(CpuAccessibleBuffer::from_data(
+                device.clone(),
+                BufferUsage::uniform_buffer(),
+                false,
+                UniformBufferObject {
+                    model: Matrix4::from_scale(1.0),
+                    view: Matrix4::from_scale(1.0),
+                    proj: Matrix4::from_scale(1.0),
+                },
+            )?)
+        })
+        .collect()
+}
+
+fn create_descriptor_sets(
+    graphics_pipeline: &Arc<Pipeline>,
+    uniform_buffers: &[Arc<CpuAccessibleBuffer<UniformBufferObject>>],
+) -> Result<Vec<Arc<dyn DescriptorSet + Send + Sync>>, Box<dyn Error>> {
+    let layout = graphics_pipeline.descriptor_set_layout(0).unwrap();
+    uniform_buffers
+        .iter()
+        .map(|uniform_buffer| {
+            let set: Arc<dyn DescriptorSet + Send + Sync> = Arc::new(
+                PersistentDescriptorSet::start(layout.clone())
+                    .add_buffer(uniform_buffer.clone())?
+                    .build()?,
+            );
+            Ok(set)
+        })
+        .collect()
+}
+
+fn create_command_buffers(
+    graph: &ResolvedGraph,
+    device: &Arc<Device>,
+    graphics_queue: &Arc<Queue>,
+    graphics_pipeline: &Arc<Pipeline>,
+    vertex_buffer: &Arc<CpuAccessibleBuffer<[Vertex]>>,
+    descriptor_sets: &[Arc<dyn DescriptorSet + Send + Sync>],
+) -> Result<Vec<Arc<AutoCommandBuffer>>, Box<dyn Error>> {
+    render_graph::record_command_buffers(
+        graph,
+        device,
+        graphics_queue,
+        descriptor_sets.len(),
+        |builder, _graph, _pass_name, image_index| {
+            Ok(builder.draw(
+                graphics_pipeline.clone(),
+                &DynamicState::none(),
+                vertex_buffer.clone(),
+                descriptor_sets[image_index].clone(),
+                (),
+            )?)
+        },
+    )
+}
+
+/// The graphics and present queue families a physical device needs for this app.
+struct QueueFamilyIndices {
+    graphics_family: Option<u32>,
+    present_family: Option<u32>,
+}
+
+impl QueueFamilyIndices {
+    fn find(physical_device: PhysicalDevice, surface: &Arc<Surface<Window>>) -> Self {
+        let mut indices = QueueFamilyIndices {
+            graphics_family: None,
+            present_family: None,
+        };
+        for queue_family in physical_device.queue_families() {
+            if indices.graphics_family.is_none() && queue_family.supports_graphics() {
+                indices.graphics_family = Some(queue_family.id());
+            }
+            if indices.present_family.is_none() && surface.is_supported(queue_family) == Ok(true) {
+                indices.present_family = Some(queue_family.id());
+            }
+            if indices.is_complete() {
+                break;
+            }
+        }
+        indices
+    }
+
+    fn is_complete(&self) -> bool {
+        self.graphics_family.is_some() && self.present_family.is_some()
+    }
+
+    /// The distinct family ids to request queues from.
+    fn unique_families(&self) -> Vec<u32> {
+        let mut families = Vec::with_capacity(2);
+        if let Some(id) = self.graphics_family {
+            families.push(id);
+        }
+        if let Some(id) = self.present_family {
+            if !families.contains(&id) {
+                families.push(id);
+            }
+        }
+        families
+    }
+}
+
+fn create_device_and_queues(
+    instance: &Arc<Instance>,
+    surface: &Arc<Surface<Window>>,
+) -> Result<(Arc<Device>, Arc<Queue>, Arc<Queue>), DeviceCreationError> {
+    let (device, indices, queues) = PhysicalDevice::enumerate(instance)
+        .filter_map(|physical_device| {
+            let indices = QueueFamilyIndices::find(physical_device, surface);
+            indices.is_complete().then(|| (physical_device, indices))
+        })
+        .find_map(|(physical_device, indices)| {
+            let extensions = DeviceExtensions {
+                khr_swapchain: true,
+                ..DeviceExtensions::supported_by_device(physical_device)
+            };
+            let queue_families = indices.unique_families().into_iter().map(|id| {
+                physical_device
+                    .queue_families()
+                    .find(|queue_family| queue_family.id() == id)
+                    .unwrap()
+            });
+            Device::new(
+                physical_device,
+                &Features::none(),
+                &extensions,
+                queue_families.map(|queue_family| (queue_family, 1.0)),
+            )
+            .ok()
+            .map(|(device, queues)| (device, indices, queues))
+        })
+        .ok_or(DeviceCreationError::FeatureNotPresent)?;
+    let queues: Vec<Arc<Queue>> = queues.collect();
+    let graphics_queue = queues
+        .iter()
+        .find(|queue| Some(queue.family().id()) == indices.graphics_family)
+        .unwrap(); // Must safe: `indices.is_complete()` held for this device
+    let present_queue = queues
+        .iter()
+        .find(|queue| Some(queue.family().id()) == indices.present_family)
+        .unwrap(); // Must safe: `indices.is_complete()` held for this device
+    Ok((device, graphics_queue.clone(), present_queue.clone()))
+}
+
+fn create_swapchain(
+    surface: &Arc<Surface<Window>>,
+    device: &Arc<Device>,
+    graphics_queue: &Arc<Queue>,
+    present_queue: &Arc<Queue>,
+) -> Result<(Arc<Swapchain<Window>>, Vec<Arc<SwapchainImage<Window>>>), SwapchainCreationError> {
+    let capabilities = surface
+        .capabilities(device.physical_device())
+        .map_err(|e| match e {
+            CapabilitiesError::OomError(e) => SwapchainCreationError::OomError(e),
+            CapabilitiesError::SurfaceLost => SwapchainCreationError::SurfaceLost,
+        })?;
+    let num_images = capabilities
+        .max_image_count
+        .unwrap_or(capabilities.min_image_count + 1)
+        .min(capabilities.min_image_count + 1);
+    let dimensions = if let Some(dimensions) = capabilities.current_extent {
+        dimensions
+    } else {
+        let [w, h]: [u32; 2] = surface.window().inner_size().into();
+        let [min_w, min_h] = capabilities.min_image_extent;
+        let [max_w, max_h] = capabilities.max_image_extent;
+        // clamp width and height
+        [min_w.max(max_w.min(w)), min_h.max(max_h.min(h))]
+    };
+    let layers = 1; // Usually 1
+    let image_usage = ImageUsage {
+        color_attachment: true,
+        ..ImageUsage::none()
+    };
+    let sharing = if graphics_queue.family() == present_queue.family() {
+        SharingMode::from(graphics_queue)
+    } else {
+        SharingMode::from(vec![graphics_queue, present_queue].as_slice())
+    };
+    let clipped = true;
+    Swapchain::new(
+        device.clone(),
+        surface.clone(),
+        num_images,
+        Format::B8G8R8A8Unorm,
+        dimensions,
+        layers,
+        image_usage,
+        sharing,
+        capabilities.current_transform,
+        CompositeAlpha::Opaque,
+        PresentMode::Fifo,
+        FullscreenExclusive::Default,
+        clipped,
+        ColorSpace::SrgbNonLinear,
+    )
+}
+
+/// Everything tied to one window surface, from the device down to the
+/// per-image command buffers.
+pub struct SurfaceBinding {
+    surface: Arc<Surface<Window>>,
+    device: Arc<Device>,
+    graphics_queue: Arc<Queue>,
+    present_queue: Arc<Queue>,
+    swapchain: Arc<Swapchain<Window>>,
+    swapchain_images: Vec<Arc<SwapchainImage<Window>>>,
+    render_passes: Vec<PassDesc>,
+    graph: ResolvedGraph,
+    vertex_shader: CompiledShader,
+    fragment_shader: CompiledShader,
+    graphics_pipeline: Arc<Pipeline>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    uniform_buffers: Vec<Arc<CpuAccessibleBuffer<UniformBufferObject>>>,
+    descriptor_sets: Vec<Arc<dyn DescriptorSet + Send + Sync>>,
+    command_buffers: Vec<Arc<AutoCommandBuffer>>,
+}
+
+impl SurfaceBinding {
+    pub fn new(
+        instance: &Arc<Instance>,
+        surface: Arc<Surface<Window>>,
+        vertices: &[Vertex],
+    ) -> Result<Self, Box<dyn Error>> {
+        let (device, graphics_queue, present_queue) = create_device_and_queues(instance, &surface)?;
+        let (swapchain, swapchain_images) =
+            create_swapchain(&surface, &device, &graphics_queue, &present_queue)?;
+
+        let (vertex_shader, fragment_shader) =
+            compile_triangle_shaders(&device).map_err(|e| -> Box<dyn Error> { e.into() })?;
+
+        let render_passes = triangle_render_graph();
+        let graph = render_graph::resolve(
+            &device,
+            &render_passes,
+            &swapchain_images,
+            swapchain.format(),
+        )?;
+        let graphics_pipeline = create_graphics_pipeline(
+            &device,
+            [
+                swapchain.dimensions()[0] as _,
+                swapchain.dimensions()[1] as _,
+            ],
+            &graph,
+            TRIANGLE_PASS,
+            &vertex_shader,
+            &fragment_shader,
+        )?;
+
+        let vertex_buffer = create_vertex_buffer(&device, vertices)?;
+        let uniform_buffers = create_uniform_buffers(&device, swapchain_images.len())?;
+        let descriptor_sets = create_descriptor_sets(&graphics_pipeline, &uniform_buffers)?;
+        let command_buffers = create_command_buffers(
+            &graph,
+            &device,
+            &graphics_queue,
+            &graphics_pipeline,
+            &vertex_buffer,
+            &descriptor_sets,
+        )?;
+
+        Ok(SurfaceBinding {
+            surface,
+            device,
+            graphics_queue,
+            present_queue,
+            swapchain,
+            swapchain_images,
+            render_passes,
+            graph,
+            vertex_shader,
+            fragment_shader,
+            graphics_pipeline,
+            vertex_buffer,
+            uniform_buffers,
+            descriptor_sets,
+            command_buffers,
+        })
+    }
+
+    pub fn device(&self) -> &Arc<Device> {
+        &self.device
+    }
+
+    pub fn graphics_queue(&self) -> &Arc<Queue> {
+        &self.graphics_queue
+    }
+
+    pub fn present_queue(&self) -> &Arc<Queue> {
+        &self.present_queue
+    }
+
+    pub fn swapchain(&self) -> &Arc<Swapchain<Window>> {
+        &self.swapchain
+    }
+
+    /// The current number of swapchain images. Changes across a [`SurfaceBinding::recreate`].
+    pub fn image_count(&self) -> usize {
+        self.swapchain_images.len()
+    }
+
+    pub fn command_buffer(&self, image_index: usize) -> Arc<AutoCommandBuffer> {
+        self.command_buffers[image_index].clone()
+    }
+
+    pub fn request_redraw(&self) {
+        self.surface.window().request_redraw();
+    }
+
+    /// Rebuilds the swapchain, render graph, pipeline, descriptor sets and
+    /// command buffers. Keeps the vertex buffer, uniform buffers and
+    /// compiled shaders.
+    pub fn recreate(&mut self) -> Result<(), Box<dyn Error>> {
+        let (swapchain, swapchain_images) = self.swapchain.recreate()?;
+        self.swapchain = swapchain;
+        self.swapchain_images = swapchain_images;
+
+        self.graph = render_graph::resolve(
+            &self.device,
+            &self.render_passes,
+            &self.swapchain_images,
+            self.swapchain.format(),
+        )?;
+        self.graphics_pipeline = create_graphics_pipeline(
+            &self.device,
+            [
+                self.swapchain.dimensions()[0] as _,
+                self.swapchain.dimensions()[1] as _,
+            ],
+            &self.graph,
+            TRIANGLE_PASS,
+            &self.vertex_shader,
+            &self.fragment_shader,
+        )?;
+        self.descriptor_sets =
+            create_descriptor_sets(&self.graphics_pipeline, &self.uniform_buffers)?;
+        self.command_buffers = create_command_buffers(
+            &self.graph,
+            &self.device,
+            &self.graphics_queue,
+            &self.graphics_pipeline,
+            &self.vertex_buffer,
+            &self.descriptor_sets,
+        )?;
+        Ok(())
+    }
+
+    /// Recompiles the shaders and rebuilds the pipeline, descriptor sets and
+    /// command buffers. Leaves everything untouched on a compile error.
+    pub fn reload_shaders(&mut self) -> Result<(), String> {
+        let (vertex_shader, fragment_shader) = compile_triangle_shaders(&self.device)?;
+        let rebuilt = create_graphics_pipeline(
+            &self.device,
+            [
+                self.swapchain.dimensions()[0] as _,
+                self.swapchain.dimensions()[1] as _,
+            ],
+            &self.graph,
+            TRIANGLE_PASS,
+            &vertex_shader,
+            &fragment_shader,
+        )
+        .map_err(|e| e.to_string())
+        .and_then(|graphics_pipeline| {
+            let descriptor_sets = create_descriptor_sets(&graphics_pipeline, &self.uniform_buffers)
+                .map_err(|e| e.to_string())?;
+            let command_buffers = create_command_buffers(
+                &self.graph,
+                &self.device,
+                &self.graphics_queue,
+                &graphics_pipeline,
+                &self.vertex_buffer,
+                &descriptor_sets,
+            )
+            .map_err(|e| e.to_string())?;
+            Ok((graphics_pipeline, descriptor_sets, command_buffers))
+        });
+
+        match rebuilt {
+            Ok((graphics_pipeline, descriptor_sets, command_buffers)) => {
+                self.vertex_shader = vertex_shader;
+                self.fragment_shader = fragment_shader;
+                self.graphics_pipeline = graphics_pipeline;
+                self.descriptor_sets = descriptor_sets;
+                self.command_buffers = command_buffers;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes a model/view/projection matrix, rotating over time, into the
+    /// uniform buffer for `image_index`.
+    pub fn update_uniform_buffer(
+        &self,
+        image_index: usize,
+        elapsed_secs: f32,
+    ) -> Result<(), Box<dyn Error>> {
+        let dimensions = self.swapchain.dimensions();
+        let model = Matrix4::from_angle_z(Rad(elapsed_secs));
+        let view = Matrix4::look_at_rh(
+            Point3::new(2.0, 2.0, 2.0),
+            Point3::new(0.0, 0.0, 0.0),
+            Vector3::new(0.0, 0.0, 1.0),
+        );
+        let aspect_ratio = dimensions[0] as f32 / dimensions[1] as f32;
+        let mut proj =
+            cgmath::perspective(Rad(std::f32::consts::FRAC_PI_4), aspect_ratio, 0.1, 10.0);
+        // Vulkan's clip space has an inverted Y compared to the OpenGL-style
+        // projection cgmath produces.
+        proj.y.y *= -1.0;
+
+        let mut write = self.uniform_buffers[image_index].write()?;
+        write.model = model;
+        write.view = view;
+        write.proj = proj;
+        Ok(())
+    }
+}
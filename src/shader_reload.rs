@@ -0,0 +1,259 @@
+//! Runtime shader hot-reload.
+//!
+//! The triangle shaders are not compiled at build time: they are compiled
+//! from `src/shader/` when the renderer starts, and this module lets that
+//! directory be watched while the app is running: when a `.vert`/`.frag` file changes,
+//! it is recompiled to SPIR-V with `shaderc` and handed back as a
+//! [`CompiledShader`] that can be turned into graphics pipeline entry points
+//! just like the macro-generated ones. A compile error is returned to the
+//! caller instead of panicking, so the previous pipeline can stay in use
+//! while a broken shader is being edited.
+
+use std::{
+    borrow::Cow,
+    ffi::CStr,
+    path::{Path, PathBuf},
+    sync::{mpsc::Receiver, Arc},
+    time::Duration,
+};
+
+use notify_debouncer_mini::{
+    new_debouncer,
+    notify::{RecommendedWatcher, RecursiveMode},
+    DebouncedEventKind, Debouncer,
+};
+use vulkano::{
+    descriptor::{
+        descriptor::{DescriptorBufferDesc, DescriptorDesc, DescriptorDescTy, ShaderStages},
+        pipeline_layout::{PipelineLayoutDesc, PipelineLayoutDescPcRange},
+    },
+    device::Device,
+    format::Format,
+    pipeline::shader::{
+        GraphicsEntryPoint, GraphicsShaderType, ShaderInterfaceDef, ShaderInterfaceDefEntry,
+        ShaderModule,
+    },
+};
+
+pub const SHADER_DIR: &str = "src/shader";
+
+/// A `ShaderInterfaceDef`/`PipelineLayoutDesc` pair with no inputs, outputs
+/// or descriptor sets. Used for `triangle.frag`'s layout (it only reads the
+/// vertex stage's output, not a uniform) and as `triangle.frag`'s output
+/// interface, which leaves its `outColor` output undescribed.
+#[derive(Debug, Copy, Clone)]
+pub struct EmptyInterface;
+
+unsafe impl ShaderInterfaceDef for EmptyInterface {
+    type Iter = std::vec::IntoIter<ShaderInterfaceDefEntry>;
+
+    fn elements(&self) -> Self::Iter {
+        Vec::new().into_iter()
+    }
+}
+
+unsafe impl PipelineLayoutDesc for EmptyInterface {
+    fn num_sets(&self) -> usize {
+        0
+    }
+
+    fn num_bindings_in_set(&self, _set: usize) -> Option<usize> {
+        None
+    }
+
+    fn descriptor(&self, _set: usize, _binding: usize) -> Option<DescriptorDesc> {
+        None
+    }
+
+    fn num_push_constants_ranges(&self) -> usize {
+        0
+    }
+
+    fn push_constants_range(&self, _num: usize) -> Option<PipelineLayoutDescPcRange> {
+        None
+    }
+}
+
+/// The layout `triangle.vert` sees: a single MVP uniform buffer at
+/// `layout(binding = 0)`, read by the vertex stage only.
+#[derive(Debug, Copy, Clone)]
+struct UniformBufferLayout;
+
+unsafe impl PipelineLayoutDesc for UniformBufferLayout {
+    fn num_sets(&self) -> usize {
+        1
+    }
+
+    fn num_bindings_in_set(&self, set: usize) -> Option<usize> {
+        match set {
+            0 => Some(1),
+            _ => None,
+        }
+    }
+
+    fn descriptor(&self, set: usize, binding: usize) -> Option<DescriptorDesc> {
+        match (set, binding) {
+            (0, 0) => Some(DescriptorDesc {
+                ty: DescriptorDescTy::Buffer(DescriptorBufferDesc {
+                    dynamic: Some(false),
+                    storage: false,
+                }),
+                array_count: 1,
+                stages: ShaderStages {
+                    vertex: true,
+                    ..ShaderStages::none()
+                },
+                readonly: true,
+            }),
+            _ => None,
+        }
+    }
+
+    fn num_push_constants_ranges(&self) -> usize {
+        0
+    }
+
+    fn push_constants_range(&self, _num: usize) -> Option<PipelineLayoutDescPcRange> {
+        None
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct VertexInput;
+
+unsafe impl ShaderInterfaceDef for VertexInput {
+    type Iter = std::vec::IntoIter<ShaderInterfaceDefEntry>;
+
+    fn elements(&self) -> Self::Iter {
+        vec![
+            ShaderInterfaceDefEntry {
+                location: 0..1,
+                format: Format::R32G32Sfloat,
+                name: Some(Cow::Borrowed("position")),
+            },
+            ShaderInterfaceDefEntry {
+                location: 1..2,
+                format: Format::R32G32B32Sfloat,
+                name: Some(Cow::Borrowed("color")),
+            },
+        ]
+        .into_iter()
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct VertexOutput;
+
+unsafe impl ShaderInterfaceDef for VertexOutput {
+    type Iter = std::vec::IntoIter<ShaderInterfaceDefEntry>;
+
+    fn elements(&self) -> Self::Iter {
+        vec![ShaderInterfaceDefEntry {
+            location: 0..1,
+            format: Format::R32G32B32Sfloat,
+            name: Some(Cow::Borrowed("fragColor")),
+        }]
+        .into_iter()
+    }
+}
+
+/// A shader module loaded from SPIR-V obtained at runtime (as opposed to
+/// `vulkano_shaders::shader!`'s build-time codegen), with enough reflection
+/// data hand-written alongside it to build graphics pipeline entry points.
+pub struct CompiledShader {
+    module: Arc<ShaderModule>,
+}
+
+impl CompiledShader {
+    /// Compiles `path` (a `.vert` or `.frag` file) to SPIR-V with `shaderc`
+    /// and loads it into a `ShaderModule`.
+    pub fn compile(device: &Arc<Device>, path: &Path) -> Result<Self, String> {
+        let kind = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("vert") => shaderc::ShaderKind::Vertex,
+            Some("frag") => shaderc::ShaderKind::Fragment,
+            other => return Err(format!("unsupported shader extension: {:?}", other)),
+        };
+        let source = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut compiler = shaderc::Compiler::new().ok_or("failed to initialize shaderc")?;
+        let artifact = compiler
+            .compile_into_spirv(&source, kind, &path.to_string_lossy(), "main", None)
+            .map_err(|e| e.to_string())?;
+        let module = unsafe { ShaderModule::new(device.clone(), artifact.as_binary_u8()) }
+            .map_err(|e| e.to_string())?;
+        Ok(Self { module })
+    }
+
+    /// Entry point for a `.vert` shader taking `Vertex` as input, reading the
+    /// MVP uniform buffer at binding 0, and producing `fragColor` (matching
+    /// `triangle.vert`).
+    pub fn vertex_main_entry_point(
+        &self,
+    ) -> GraphicsEntryPoint<(), VertexInput, VertexOutput, UniformBufferLayout> {
+        unsafe {
+            self.module.graphics_entry_point(
+                CStr::from_bytes_with_nul_unchecked(b"main\0"),
+                VertexInput,
+                VertexOutput,
+                UniformBufferLayout,
+                GraphicsShaderType::Vertex,
+            )
+        }
+    }
+
+    /// Entry point for a `.frag` shader taking `fragColor` as input and
+    /// writing `outColor` (matching `triangle.frag`).
+    pub fn fragment_main_entry_point(
+        &self,
+    ) -> GraphicsEntryPoint<(), VertexOutput, EmptyInterface, EmptyInterface> {
+        unsafe {
+            self.module.graphics_entry_point(
+                CStr::from_bytes_with_nul_unchecked(b"main\0"),
+                VertexOutput,
+                EmptyInterface,
+                EmptyInterface,
+                GraphicsShaderType::Fragment,
+            )
+        }
+    }
+}
+
+/// Watches `src/shader/` and reports the paths of `.vert`/`.frag` files as
+/// they are written to, debounced so a single save doesn't fire twice.
+pub struct ShaderWatcher {
+    _debouncer: Debouncer<RecommendedWatcher>,
+    events: Receiver<PathBuf>,
+}
+
+impl ShaderWatcher {
+    pub fn new() -> notify_debouncer_mini::notify::Result<Self> {
+        let (sender, events) = std::sync::mpsc::channel();
+        let mut debouncer = new_debouncer(Duration::from_millis(200), None, move |result| {
+            if let Ok(events) = result {
+                for event in events {
+                    if event.kind == DebouncedEventKind::Any && is_shader_source(&event.path) {
+                        let _ = sender.send(event.path);
+                    }
+                }
+            }
+        })?;
+        debouncer
+            .watcher()
+            .watch(Path::new(SHADER_DIR), RecursiveMode::NonRecursive)?;
+        Ok(Self {
+            _debouncer: debouncer,
+            events,
+        })
+    }
+
+    /// Drains the paths that changed since the last poll. Never blocks.
+    pub fn poll_changes(&self) -> Vec<PathBuf> {
+        self.events.try_iter().collect()
+    }
+}
+
+fn is_shader_source(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("vert") | Some("frag")
+    )
+}